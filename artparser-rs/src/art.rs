@@ -2,11 +2,50 @@ use std::{
     io::{self, Read},
     fs::File,
     path::Path,
+    str::Utf8Error,
 };
 
+use nom::error::{ErrorKind, VerboseError, VerboseErrorKind};
+
 use lzunpack::{decompress, DecodeError};
-use parser::{read_compressed, read_content};
+use parser::{read_compressed_with_limits, read_content_with_limits, ParseLimits};
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+/// Turns a nom parse failure into a message naming which field-level
+/// context it happened in and how many bytes into `original` the failing
+/// input started, instead of the opaque `"failed parse ..."` strings this
+/// used to produce.
+fn describe_parse_error(original: &[u8], err: nom::Err<VerboseError<&[u8]>>) -> String {
+    match err {
+        nom::Err::Incomplete(_) => String::from("not enough data"),
+        nom::Err::Error(e) | nom::Err::Failure(e) => {
+            e.errors.iter()
+                .map(|(input, kind)| format!("{:?} at offset {}", kind, original.len() - input.len()))
+                .collect::<Vec<_>>()
+                .join("; ")
+        }
+    }
+}
+
+/// `read_str`/`read_const_str` tag a UTF-8 failure with `ErrorKind::Char`
+/// on the byte slice of the field that didn't decode (for `read_const_str`,
+/// the whole fixed-width field); pulls that slice back out
+/// (re-deriving the real `Utf8Error` from it) so callers get a typed
+/// `ArtError::Utf8Error` instead of a generic parse-failure string.
+fn find_utf8_error<'a>(err: &nom::Err<VerboseError<&'a [u8]>>) -> Option<Utf8Error> {
+    let verbose = match err {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e,
+        nom::Err::Incomplete(_) => return None,
+    };
+    verbose.errors.iter().find_map(|(input, kind)| match kind {
+        VerboseErrorKind::Nom(ErrorKind::Char) => std::str::from_utf8(input).err(),
+        _ => None,
+    })
+}
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ArtFile {
     pub version: usize,
     pub active_layer: usize,
@@ -24,19 +63,40 @@ pub struct ArtFile {
 
 impl ArtFile {
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<ArtFile, ArtError> {
+        ArtFile::from_path_with_limits(path, &ParseLimits::default())
+    }
+    pub fn from_path_with_limits<P: AsRef<Path>>(path: P, limits: &ParseLimits) -> Result<ArtFile, ArtError> {
         let mut buf = Vec::new();
         let mut file = File::open(path)?;
         file.read_to_end(&mut buf)?;
-        ArtFile::from_bytes(&buf[..])
+        ArtFile::from_bytes_with_limits(&buf[..], limits)
     }
     pub fn from_bytes(bytes: &[u8]) -> Result<ArtFile, ArtError> {
-        let (compressed, (_ver, pins, _rest_len)) = read_compressed(bytes)
-            .map_err(|_e| ArtError::ParsingError(String::from("failed parse compressed")))?;
+        ArtFile::from_bytes_with_limits(bytes, &ParseLimits::default())
+    }
+    /// Like `from_bytes`, but lets the caller bound how large the
+    /// length-prefixed collections and embedded images inside `bytes` are
+    /// allowed to be -- useful when `bytes` isn't trusted.
+    pub fn from_bytes_with_limits(bytes: &[u8], limits: &ParseLimits) -> Result<ArtFile, ArtError> {
+        let (compressed, (_ver, pins, _rest_len)) = match read_compressed_with_limits(bytes, limits) {
+            Ok(ok) => ok,
+            Err(e) => return Err(match find_utf8_error(&e) {
+                Some(utf8_err) => ArtError::Utf8Error(utf8_err),
+                None => ArtError::ParsingError(
+                    format!("failed to parse header: {}", describe_parse_error(bytes, e))),
+            }),
+        };
 
         let decompressed = decompress(compressed)?;
 
-        let (_, mut art) = read_content(&decompressed[..])
-            .map_err(|_e| ArtError::ParsingError(String::from("failed parse decompressed")))?;
+        let (_, mut art) = match read_content_with_limits(&decompressed[..], limits) {
+            Ok(ok) => ok,
+            Err(e) => return Err(match find_utf8_error(&e) {
+                Some(utf8_err) => ArtError::Utf8Error(utf8_err),
+                None => ArtError::ParsingError(
+                    format!("failed to parse content: {}", describe_parse_error(&decompressed, e))),
+            }),
+        };
 
         art.pins = pins;
 
@@ -45,6 +105,7 @@ impl ArtFile {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RGB {
     pub r: u8,
     pub g: u8,
@@ -52,6 +113,7 @@ pub struct RGB {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PenInfo {
     pub kind: u32,
     pub color: RGB,
@@ -64,12 +126,14 @@ pub struct PenInfo {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ArtPin {
     pub matrix: [f32; 16],
     pub name: String,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LayerInfo {
     pub visible: bool,
     pub opacity: f32,
@@ -79,12 +143,110 @@ pub struct LayerInfo {
     pub zoom: f32,
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Image {
     pub kind: u32,
+    #[cfg_attr(feature = "serde", serde(with = "image_raw_base64"))]
     pub raw: Vec<u8>,
 }
 
+/// Serializes `Image.raw` as a base64 string instead of a JSON array of
+/// bytes, the way Maraiah's `serde_obj` feature handles raw byte blobs.
+#[cfg(feature = "serde")]
+mod image_raw_base64 {
+    use serde::de::Error;
+    use super::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(raw: &Vec<u8>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&base64::encode(raw))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(d)?;
+        base64::decode(&encoded).map_err(D::Error::custom)
+    }
+}
+
+/// Image codecs `Image::decode` knows how to dispatch to, determined by
+/// sniffing `raw`'s magic bytes -- `kind`'s own encoding hasn't been
+/// reverse-engineered far enough to map specific values to specific
+/// codecs.
+///
+/// Note this means `kind` is parsed and stored but never consulted here --
+/// `Image::format` is entirely content-sniffed. Don't assume `kind` is
+/// authoritative for format selection until someone maps its values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    /// Not a recognized container: a tightly packed RGBA8 buffer whose
+    /// dimensions must come from elsewhere, e.g. a correlated
+    /// `DrawImage::src_size`.
+    RawRgba,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DecodedImage {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Unpremultiplied RGBA8, row-major, no row padding.
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn format(&self) -> ImageFormat {
+        if self.raw.starts_with(b"\x89PNG\r\n\x1a\n") {
+            ImageFormat::Png
+        } else if self.raw.starts_with(b"\xff\xd8") {
+            ImageFormat::Jpeg
+        } else {
+            ImageFormat::RawRgba
+        }
+    }
+
+    /// Decodes `self.raw` into pixels. Only works for self-describing
+    /// containers (PNG, JPEG), since those carry their own width/height;
+    /// a buffer that sniffs as `ImageFormat::RawRgba` has none, so use
+    /// `decode_with_size` for that case instead.
+    pub fn decode(&self) -> Result<DecodedImage, ArtError> {
+        let format = self.format();
+        if format == ImageFormat::RawRgba {
+            return Err(ArtError::ParsingError(String::from(
+                "raw image has no embedded dimensions; use Image::decode_with_size")));
+        }
+        let decoded = image::load_from_memory(&self.raw)
+            .map_err(|e| ArtError::ParsingError(format!("failed to decode image: {}", e)))?
+            .to_rgba8();
+        Ok(DecodedImage {
+            format,
+            width: decoded.width(),
+            height: decoded.height(),
+            pixels: decoded.into_raw(),
+        })
+    }
+
+    /// Decodes a raw (non-PNG/JPEG) buffer as tightly packed RGBA8 of the
+    /// given dimensions, e.g. the `src_size` carried by the `DrawImage`
+    /// action that references this image.
+    pub fn decode_with_size(&self, width: u32, height: u32) -> Result<DecodedImage, ArtError> {
+        let expected = width as usize * height as usize * 4;
+        if self.raw.len() != expected {
+            return Err(ArtError::ParsingError(format!(
+                "raw image is {} bytes, expected {}x{}x4={}", self.raw.len(), width, height, expected)));
+        }
+        Ok(DecodedImage {
+            format: ImageFormat::RawRgba,
+            width,
+            height,
+            pixels: self.raw.clone(),
+        })
+    }
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Action {
     Stroke { points: Vec<StrokePoint> },
     _Unknown08(u32),
@@ -108,9 +270,16 @@ pub enum Action {
         image_id: usize,
     },
     _Unknown05([u8;0x14]),
+    /// An opcode not in `art_action!`'s dispatch table. The format has no
+    /// per-action length prefix, so there's no way to know how many bytes
+    /// belong to an opcode we don't recognize -- `bytes` holds everything
+    /// left in the action stream, and this ends up being the last entry
+    /// `read_content_with_limits`'s action list can produce.
+    UnknownOpcode { opcode: u32, bytes: Vec<u8> },
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StrokePoint {
     pub x: f32,
     pub y: f32,
@@ -118,6 +287,7 @@ pub struct StrokePoint {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PenUpdate {
     pub kind: u32,
     pub noise: f32,
@@ -128,6 +298,7 @@ pub struct PenUpdate {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PasteProps {
     pub from_layer: usize,
     pub rect: [f32; 4],
@@ -141,8 +312,10 @@ pub struct PasteProps {
 pub enum ArtError {
     ParsingError(String),
     IoError(io::Error),
-    //FromUtf8Error(FromUtf8Error),
-    //Utf8Error(Utf8Error),
+    /// A `read_str`/`read_const_str` field wasn't valid UTF-8. Reported as
+    /// its own variant (rather than folded into `ParsingError`'s string)
+    /// so callers can match on "this was a UTF-8 problem" specifically.
+    Utf8Error(Utf8Error),
     DecompressError(DecodeError),
     //BadMagic(u32),
     //BadVersion(u32),
@@ -151,12 +324,139 @@ pub enum ArtError {
 impl From<io::Error> for ArtError {
     fn from(err: io::Error) -> ArtError { ArtError::IoError(err) }
 }
-//impl From<FromUtf8Error> for ArtError {
-//    fn from(err: FromUtf8Error) -> ArtError { ArtError::FromUtf8Error(err) }
-//}
-//impl From<Utf8Error> for ArtError {
-//    fn from(err: Utf8Error) -> ArtError { ArtError::Utf8Error(err) }
-//}
 impl From<DecodeError> for ArtError {
     fn from(err: DecodeError) -> ArtError { ArtError::DecompressError(err) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lzunpack::compress;
+
+    /// Builds a minimal, otherwise-valid `read_content_with_limits` body
+    /// with one layer whose name starts with an invalid UTF-8 byte.
+    fn content_with_bad_layer_name() -> Vec<u8> {
+        let mut c = Vec::new();
+        c.extend(&0u32.to_le_bytes()); // version
+        c.extend(&0u32.to_le_bytes()); // active_layer
+        c.extend(&0u32.to_le_bytes()); // unused
+        c.extend(&[0u8, 0, 0]); // background_color
+        c.extend(&1.0f32.to_le_bytes()); // background_alpha
+        for _ in 0..4 { c.extend(&0u32.to_le_bytes()); } // unused
+        // pen_info
+        c.extend(&0u32.to_le_bytes()); // kind
+        c.extend(&[0u8, 0, 0]); // color
+        for _ in 0..5 { c.extend(&0.0f32.to_le_bytes()); } // noise/size/size_min/opacity/opacity_min
+        c.extend(&0u32.to_le_bytes()); // is_eraser
+        for _ in 0..2 { c.extend(&0u32.to_le_bytes()); } // unused
+        for _ in 0..16 { c.extend(&0.0f32.to_le_bytes()); } // view_matrix
+        c.extend(&1.0f32.to_le_bytes()); // view_zoom
+        c.extend(&0u32.to_le_bytes()); // layer_order: 0 entries
+        c.extend(&1u32.to_le_bytes()); // layers: 1 entry
+        c.extend(&1u32.to_le_bytes()); // visible
+        c.extend(&1.0f32.to_le_bytes()); // opacity
+        let mut name = vec![0u8; 256];
+        name[0] = 0xff; // not valid UTF-8, and not a continuation byte either
+        c.extend(&name);
+        c.extend(&0u32.to_le_bytes()); // action_count
+        for _ in 0..16 { c.extend(&0.0f32.to_le_bytes()); } // layer matrix
+        c.extend(&1.0f32.to_le_bytes()); // layer zoom
+        c.extend(&0u32.to_le_bytes()); // images: 0 entries
+        c.extend(&0u32.to_le_bytes()); // actions: 0 entries
+        c
+    }
+
+    #[test]
+    fn invalid_utf8_layer_name_reports_typed_utf8_error() {
+        let content = content_with_bad_layer_name();
+        match read_content_with_limits(&content, &ParseLimits::default()) {
+            Ok(_) => panic!("a layer name starting with 0xff isn't valid UTF-8"),
+            Err(err) => match find_utf8_error(&err) {
+                Some(_) => {}
+                None => panic!("expected a UTF-8 error, got: {}", describe_parse_error(&content, err)),
+            },
+        }
+    }
+
+    #[test]
+    fn from_bytes_surfaces_utf8_error_for_malformed_layer_name() {
+        let content = content_with_bad_layer_name();
+        let compressed = compress(&content);
+
+        let mut bytes = Vec::new();
+        bytes.extend(b"\xc5\xb3\x8b\xe7"); // magic
+        bytes.extend(&0u32.to_le_bytes()); // version-0 tag
+        bytes.extend(&[0u8; 8]); // version-0 padding
+        bytes.extend(&0u32.to_le_bytes()); // declared compressed length (unused downstream)
+        bytes.extend(&compressed);
+
+        match ArtFile::from_bytes(&bytes) {
+            Err(ArtError::Utf8Error(_)) => {}
+            other => panic!("expected ArtError::Utf8Error, got: {:?}", other.map(|_| ())),
+        }
+    }
+
+    fn encode_test_png(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+        let img = image::RgbaImage::from_raw(width, height, pixels.to_vec())
+            .expect("test pixel buffer should match width/height");
+        let mut out = Vec::new();
+        image::DynamicImage::ImageRgba8(img)
+            .write_to(&mut out, image::ImageOutputFormat::Png)
+            .expect("encoding the test PNG should succeed");
+        out
+    }
+
+    #[test]
+    fn format_sniffs_png_jpeg_and_raw_from_magic_bytes() {
+        let png = Image { kind: 0, raw: encode_test_png(1, 1, &[10, 20, 30, 255]) };
+        assert_eq!(png.format(), ImageFormat::Png);
+
+        let jpeg = Image { kind: 0, raw: vec![0xff, 0xd8, 0xff, 0xe0] };
+        assert_eq!(jpeg.format(), ImageFormat::Jpeg);
+
+        let raw = Image { kind: 0, raw: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        assert_eq!(raw.format(), ImageFormat::RawRgba);
+    }
+
+    #[test]
+    fn decode_round_trips_a_png_image() {
+        let pixels = [10u8, 20, 30, 255, 40, 50, 60, 255, 70, 80, 90, 255, 100, 110, 120, 255];
+        let image = Image { kind: 0, raw: encode_test_png(2, 2, &pixels) };
+
+        let decoded = image.decode().expect("a well-formed PNG should decode");
+        assert_eq!(decoded.format, ImageFormat::Png);
+        assert_eq!(decoded.width, 2);
+        assert_eq!(decoded.height, 2);
+        assert_eq!(decoded.pixels, pixels);
+    }
+
+    #[test]
+    fn decode_rejects_raw_images_since_they_have_no_embedded_dimensions() {
+        let image = Image { kind: 0, raw: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        match image.decode() {
+            Err(ArtError::ParsingError(_)) => {}
+            other => panic!("expected ArtError::ParsingError, got: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn decode_with_size_reads_a_tightly_packed_raw_buffer() {
+        let pixels = vec![9u8, 8, 7, 6, 5, 4, 3, 2];
+        let image = Image { kind: 0, raw: pixels.clone() };
+
+        let decoded = image.decode_with_size(1, 2).expect("a correctly sized raw buffer should decode");
+        assert_eq!(decoded.format, ImageFormat::RawRgba);
+        assert_eq!(decoded.width, 1);
+        assert_eq!(decoded.height, 2);
+        assert_eq!(decoded.pixels, pixels);
+    }
+
+    #[test]
+    fn decode_with_size_rejects_a_buffer_with_the_wrong_byte_count() {
+        let image = Image { kind: 0, raw: vec![1, 2, 3, 4] };
+        match image.decode_with_size(2, 2) {
+            Err(ArtError::ParsingError(_)) => {}
+            other => panic!("expected ArtError::ParsingError, got: {:?}", other.map(|_| ())),
+        }
+    }
+}
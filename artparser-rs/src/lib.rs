@@ -1,8 +1,23 @@
 extern crate byteorder;
+extern crate image;
 extern crate nom;
 
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+extern crate base64;
+
 mod art;
 mod lzunpack;
 mod parser;
-pub use art::{ArtFile, ArtError};
-pub use lzunpack::decompress;
+mod render;
+pub use art::{ArtFile, ArtError, DecodedImage, ImageFormat};
+pub use lzunpack::{
+    compress, compress_with_options, decompress, decompress_members, decompress_with_options,
+    CompressOptions, DecodeError, DecodeOptions, Decoder,
+};
+pub use parser::ParseLimits;
+pub use render::{encode_png, render, RenderOptions, RenderedImage};
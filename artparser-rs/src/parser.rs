@@ -1,23 +1,53 @@
 use nom::{
     IResult,
+    InputLength,
     ToUsize,
     branch::{alt},
     bytes::complete::{tag, take},
-    combinator::{map, rest_len},
-    error::{ErrorKind, ParseError, VerboseError},
+    combinator::{map, rest, rest_len},
+    error::{ErrorKind, ParseError, VerboseError, VerboseErrorKind},
     number::complete::{le_u8, le_u32, le_f32},
-    multi::{count, length_data, length_value},
-    sequence::{preceded, tuple},
+    multi::{count, length_value},
+    sequence::{tuple},
 };
 
 use art::*;
 
+/// Limits on attacker-controlled counts and lengths, so a hostile file
+/// can't force an unbounded allocation or a multi-minute parse just by
+/// lying about how many elements (or how many image bytes) follow.
+/// Defaults are generous but finite.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    pub max_elements: usize,
+    pub max_image_bytes: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> ParseLimits {
+        ParseLimits {
+            max_elements: 1_000_000,
+            max_image_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+// Smallest number of bytes a single element of each length-counted
+// collection can occupy, used to sanity-check a declared count against
+// how much input actually remains before looping over it.
+const MIN_PIN_SIZE: usize = 0x40 + 4; // matrix + string length prefix
+const MIN_LAYER_INFO_SIZE: usize = 4 + 4 + 0x100 + 4 + 0x40 + 4;
+const MIN_IMAGE_SIZE: usize = 4 + 4; // kind + length prefix
+const MIN_ACTION_SIZE: usize = 4 + 4; // action id + opcode tag
+
 fn length_count<I, O, E, N, C, F>(
+    limits: ParseLimits,
+    min_element_size: usize,
     c: C,
     f: F,
 ) -> impl Fn(I) -> IResult<I, Vec<O>, E>
 where
-    I: Clone + PartialEq,
+    I: Clone + PartialEq + InputLength,
     N: ToUsize,
     C: Fn(I) -> IResult<I, N, E>,
     F: Fn(I) -> IResult<I, O, E>,
@@ -29,9 +59,17 @@ where
         let (i, count) = c(input.clone())?;
         input = i.clone();
 
-        let mut res = Vec::new();
+        let count = count.to_usize();
+        let too_many = count > limits.max_elements;
+        let too_big_for_input = min_element_size > 0
+            && count > input.input_len() / min_element_size;
+        if too_many || too_big_for_input {
+            return Err(nom::Err::Error(E::from_error_kind(i, ErrorKind::TooLarge)));
+        }
+
+        let mut res = Vec::with_capacity(count);
 
-        for _ in 0..count.to_usize() {
+        for _ in 0..count {
             let input_ = input.clone();
             match f(input_) {
                 Ok((i, o)) => {
@@ -51,7 +89,22 @@ where
     }
 }
 
-pub fn read_compressed<'a>(i: &'a[u8])
+/// Like `length_data(le_u32)`, but rejects a declared length over
+/// `max_bytes` before ever calling `take`.
+fn length_data_bounded<'a, E>(max_bytes: usize) -> impl Fn(&'a[u8]) -> IResult<&'a[u8], &'a[u8], E>
+where
+    E: ParseError<&'a[u8]>,
+{
+    move |i: &'a[u8]| {
+        let (i, len) = le_u32(i)?;
+        if len as usize > max_bytes {
+            return Err(nom::Err::Error(E::from_error_kind(i, ErrorKind::TooLarge)));
+        }
+        take(len)(i)
+    }
+}
+
+pub fn read_compressed_with_limits<'a>(i: &'a[u8], limits: &ParseLimits)
 -> IResult<&'a[u8], (usize, Vec<ArtPin>, usize), VerboseError<&'a[u8]>>
 {
     let ver00 = map(tuple((tag(b"\x00\x00\x00\x00"), take(0x08_u8))),
@@ -59,7 +112,7 @@ pub fn read_compressed<'a>(i: &'a[u8])
     let ver81 = map(tuple((tag(b"\x81\x00\x00\x00"), take(0x1c_u8))),
                     |_| (0x81_usize, Vec::new()));
     let ver82 = map(tuple((tag(b"\x82\x00\x00\x00"), take(0x21_u8),
-                          length_count(le_u32, read_pin))),
+                          length_count(*limits, MIN_PIN_SIZE, le_u32, read_pin))),
                     |t| (0x82_usize, t.2));
 
     let file = tuple((
@@ -73,7 +126,7 @@ pub fn read_compressed<'a>(i: &'a[u8])
     })(i)
 }
 
-pub fn read_content<'a>(i: &'a[u8])
+pub fn read_content_with_limits<'a>(i: &'a[u8], limits: &ParseLimits)
 -> IResult<&'a[u8], ArtFile, VerboseError<&'a[u8]>>
 {
     let file = tuple((
@@ -87,10 +140,10 @@ pub fn read_content<'a>(i: &'a[u8])
         le_u32, le_u32,
         read_matrix,
         le_f32,
-        length_count(le_u32, map(le_u32, |t|t.to_usize())),
-        length_count(le_u32, read_layer_info),
-        length_count(le_u32, read_image),
-        length_count(le_u32, read_action),
+        length_count(*limits, 4, le_u32, map(le_u32, |t|t.to_usize())),
+        length_count(*limits, MIN_LAYER_INFO_SIZE, le_u32, read_layer_info),
+        length_count(*limits, MIN_IMAGE_SIZE, le_u32, |i| read_image_with_limits(i, limits)),
+        length_count(*limits, MIN_ACTION_SIZE, le_u32, read_action),
         rest_len,
     ));
 
@@ -171,16 +224,25 @@ fn read_pin<'a>(i: &'a[u8])
 fn read_str<'a>(i: &'a[u8])
 -> IResult<&'a[u8], &'a str, VerboseError<&'a[u8]>>
 {
-    length_value(le_u32, |i| Ok((b"", std::str::from_utf8(i).expect("couldn't read str?"))))(i)
+    length_value(le_u32, |buf: &'a[u8]| {
+        match std::str::from_utf8(buf) {
+            Ok(s) => Ok((b"" as &[u8], s)),
+            Err(_) => Err(nom::Err::Error(VerboseError::from_error_kind(buf, ErrorKind::Char))),
+        }
+    })(i)
 }
 
 fn read_const_str<'a>(i: &'a[u8])
 -> IResult<&'a[u8], &'a str, VerboseError<&'a[u8]>>
 {
-    map(take(256_usize), |buf: &[u8]| {
-        let start = buf.split(|&c| c == 0).next().unwrap();
-        std::str::from_utf8(&start).expect("couldn't read const str?")
-    })(i)
+    let (i, buf) = take(256_usize)(i)?;
+    // `split` always yields at least one (possibly empty) slice, so this
+    // never panics even on a buffer with no NUL terminator.
+    let start = buf.split(|&c| c == 0).next().unwrap();
+    match std::str::from_utf8(start) {
+        Ok(s) => Ok((i, s)),
+        Err(_) => Err(nom::Err::Error(VerboseError::from_error_kind(buf, ErrorKind::Char))),
+    }
 }
 
 fn read_layer_info<'a>(i: &'a[u8])
@@ -198,40 +260,58 @@ fn read_layer_info<'a>(i: &'a[u8])
     })(i)
 }
 
-fn read_image<'a>(i: &'a[u8])
+fn read_image_with_limits<'a>(i: &'a[u8], limits: &ParseLimits)
 -> IResult<&'a[u8], Image, VerboseError<&'a[u8]>>
 {
-    map(tuple((le_u32, length_data(le_u32))), |(kind, slice)| {
+    map(tuple((le_u32, length_data_bounded(limits.max_image_bytes))), |(kind, slice)| {
         Image { kind, raw: slice.to_vec() }
     })(i)
 }
 
-fn read_action<'a>(i: &'a[u8])
--> IResult<&'a[u8], (usize, Action), VerboseError<&'a[u8]>>
-{
-    tuple((
-        map(le_u32, |i|i.to_usize()),
-        alt((
-            read_action_stroke,
-            read_action_05,
-            read_action_08,
-            read_action_pen_transform,
-            read_action_pen_props,
-            read_action_pen_color,
-            read_action_pen_is_eraser,
-            read_action_paste_layer,
-            read_action_layer_transform,
-            read_action_cut_rect,
-            read_action_layer_merge,
-            read_action_draw_image,
-        ))
-    ))(i)
+// Declares the opcode dispatch table for `read_action`: one line per
+// action, mapping its opcode to the parser that reads everything after
+// it. This is the single source of truth for the mapping -- adding an
+// action means adding one row here, instead of also repeating the opcode
+// as a `tag()` inside the handler and adding it to a hand-maintained
+// `alt((...))`. Opcodes that aren't listed hit the fallback arm below,
+// which stores the opcode and the rest of the action stream as
+// `Action::UnknownOpcode` instead of failing `read_action` the way an
+// exhausted `alt` would.
+macro_rules! art_action {
+    ( $( $opcode:expr => $parser:ident ),+ $(,)? ) => {
+        fn read_action<'a>(i: &'a[u8])
+        -> IResult<&'a[u8], (usize, Action), VerboseError<&'a[u8]>>
+        {
+            let (i, id) = map(le_u32, |v: u32| v.to_usize())(i)?;
+            let (i, opcode) = le_u32(i)?;
+            let (i, action) = match opcode {
+                $( $opcode => $parser(i)?, )+
+                _ => read_action_unknown(opcode)(i)?,
+            };
+            Ok((i, (id, action)))
+        }
+    };
+}
+
+art_action! {
+    0x01 => read_action_stroke,
+    0x05 => read_action_05,
+    0x07 => read_action_draw_image,
+    0x08 => read_action_08,
+    0x0c => read_action_layer_merge,
+    0x0d => read_action_layer_transform,
+    0x0e => read_action_cut_rect,
+    0x0f => read_action_paste_layer,
+    0x33 => read_action_pen_transform,
+    0x34 => read_action_pen_props,
+    0x35 => read_action_pen_color,
+    0x36 => read_action_pen_is_eraser,
 }
 
 fn read_action_stroke<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    let (mut input, count) = preceded(tag(b"\x01\x00\x00\x00"), le_u32)(i)?;
+    let (mut input, count) = le_u32(i)?;
     let mut points = Vec::new();
     if count > 0 {
         let (i, mut point) = map(tuple((le_f32, le_f32, le_f32)), |t| {
@@ -261,140 +341,222 @@ fn read_action_stroke<'a>(i: &'a[u8])
 fn read_action_pen_transform<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x33\x00\x00\x00"),
-        map(tuple((read_matrix, le_f32)),
-            |(matrix, zoom)| Action::PenTransform { matrix, zoom })
-    )(i)
+    map(tuple((read_matrix, le_f32)),
+        |(matrix, zoom)| Action::PenTransform { matrix, zoom })(i)
 }
 
 fn read_action_pen_props<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x34\x00\x00\x00"),
-        map(tuple((le_u32, le_f32, le_f32, le_f32, le_f32, le_f32)),
-            |t| Action::PenProperties(PenUpdate {
-                kind: t.0,
-                noise: t.1,
-                size: t.2,
-                size_min: t.3,
-                opacity: t.4,
-                opacity_min: t.5,
-            }))
-    )(i)
+    map(tuple((le_u32, le_f32, le_f32, le_f32, le_f32, le_f32)),
+        |t| Action::PenProperties(PenUpdate {
+            kind: t.0,
+            noise: t.1,
+            size: t.2,
+            size_min: t.3,
+            opacity: t.4,
+            opacity_min: t.5,
+        }))(i)
 }
 
 fn read_action_pen_color<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x35\x00\x00\x00"),
-        map(read_rgb, Action::PenColor)
-    )(i)
+    map(read_rgb, Action::PenColor)(i)
 }
 
 fn read_action_pen_is_eraser<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x36\x00\x00\x00"),
-        map(le_u32, |v| Action::PenIsEraser(v != 0))
-    )(i)
+    map(le_u32, |v| Action::PenIsEraser(v != 0))(i)
 }
 
 fn read_action_paste_layer<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x0f\x00\x00\x00"),
-        map(tuple((le_u32, read_rect,
-                   read_matrix, le_f32,
-                   read_matrix, le_f32)),
-            |t| Action::PasteLayer(PasteProps {
-                from_layer: t.0.to_usize(),
-                rect: t.1,
-                matrix1: t.2,
-                zoom1: t.3,
-                matrix2: t.4,
-                zoom2: t.5,
-            }))
-    )(i)
+    map(tuple((le_u32, read_rect,
+               read_matrix, le_f32,
+               read_matrix, le_f32)),
+        |t| Action::PasteLayer(PasteProps {
+            from_layer: t.0.to_usize(),
+            rect: t.1,
+            matrix1: t.2,
+            zoom1: t.3,
+            matrix2: t.4,
+            zoom2: t.5,
+        }))(i)
 }
 
 fn read_action_layer_transform<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x0d\x00\x00\x00"),
-        map(tuple((read_matrix, le_f32)),
-            |(matrix, zoom)| Action::LayerTransform {
-                matrix, zoom,
-            })
-    )(i)
+    map(tuple((read_matrix, le_f32)),
+        |(matrix, zoom)| Action::LayerTransform {
+            matrix, zoom,
+        })(i)
 }
 
 fn read_action_cut_rect<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x0e\x00\x00\x00"),
-        map(read_rect, |rect| Action::CutRect { rect })
-    )(i)
+    map(read_rect, |rect| Action::CutRect { rect })(i)
 }
 
 fn read_action_layer_merge<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x0c\x00\x00\x00"),
-        map(tuple((le_u32, le_f32, le_f32,
-                    read_matrix, le_f32)),
-            |t| Action::LayerMerge {
-                other: t.0.to_usize(),
-                opacity_src: t.1,
-                opacity_dst: t.2,
-                matrix: t.3,
-                zoom: t.4,
-            })
-    )(i)
+    map(tuple((le_u32, le_f32, le_f32,
+                read_matrix, le_f32)),
+        |t| Action::LayerMerge {
+            other: t.0.to_usize(),
+            opacity_src: t.1,
+            opacity_dst: t.2,
+            matrix: t.3,
+            zoom: t.4,
+        })(i)
 }
 
 fn read_action_draw_image<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x07\x00\x00\x00"),
-        map(tuple((le_f32, le_f32, le_f32, le_f32,
-                   le_u32, le_u32, le_u32, le_u32)),
-            |t| Action::DrawImage {
-                dst_center: [t.0, t.1],
-                dst_size: [t.2, t.3],
-                _unknown: t.4,
-                src_size: [t.5, t.6],
-                image_id: t.7.to_usize(),
-            })
-    )(i)
+    map(tuple((le_f32, le_f32, le_f32, le_f32,
+               le_u32, le_u32, le_u32, le_u32)),
+        |t| Action::DrawImage {
+            dst_center: [t.0, t.1],
+            dst_size: [t.2, t.3],
+            _unknown: t.4,
+            src_size: [t.5, t.6],
+            image_id: t.7.to_usize(),
+        })(i)
+}
+
+/// Fallback for opcodes not in `art_action!`'s table. There's no
+/// per-action length prefix anywhere in this format, so for an opcode we
+/// don't know how to parse, there's no way to tell how many bytes belong
+/// to it and where the next action would start -- the best we can do
+/// honestly is capture the opcode and everything left in the action
+/// stream, which means this is necessarily the last action
+/// `read_content_with_limits` will produce once it's hit. If the action
+/// count declares further actions after it, reading the next one fails for
+/// lack of input and so does the whole parse.
+fn read_action_unknown<'a>(opcode: u32) -> impl Fn(&'a[u8]) -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>> {
+    move |i: &'a[u8]| {
+        map(rest, move |bytes: &'a[u8]| Action::UnknownOpcode { opcode, bytes: bytes.to_vec() })(i)
+    }
 }
 
 fn read_action_05<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x05\x00\x00\x00"),
-        map(take(0x14_usize), |t| {
-            let mut buf = [0_u8; 0x14];
-            buf.copy_from_slice(t);
-            Action::_Unknown05(buf)
-        })
-    )(i)
+    map(take(0x14_usize), |t| {
+        let mut buf = [0_u8; 0x14];
+        buf.copy_from_slice(t);
+        Action::_Unknown05(buf)
+    })(i)
 }
 
 fn read_action_08<'a>(i: &'a[u8])
 -> IResult<&'a[u8], Action, VerboseError<&'a[u8]>>
 {
-    preceded(
-        tag(b"\x08\x00\x00\x00"),
-        map(le_u32, Action::_Unknown08)
-    )(i)
+    map(le_u32, Action::_Unknown08)(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_action_accepts_unrecognized_opcode_instead_of_failing() {
+        let mut input = Vec::new();
+        input.extend_from_slice(&7u32.to_le_bytes()); // action id
+        input.extend_from_slice(&0x9999u32.to_le_bytes()); // opcode, not in art_action!'s table
+        input.extend_from_slice(&[1, 2, 3, 4]); // rest of the action stream
+
+        let (rest, (id, action)) = read_action(&input).expect("unknown opcode should not fail the parse");
+        assert!(rest.is_empty());
+        assert_eq!(id, 7);
+        match action {
+            Action::UnknownOpcode { opcode, bytes } => {
+                assert_eq!(opcode, 0x9999);
+                assert_eq!(bytes, vec![1, 2, 3, 4]);
+            }
+            other => panic!("expected UnknownOpcode, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_action_still_dispatches_known_opcodes() {
+        let mut input = Vec::new();
+        input.extend_from_slice(&1u32.to_le_bytes()); // action id
+        input.extend_from_slice(&0x35u32.to_le_bytes()); // PenColor opcode
+        input.extend_from_slice(&[10, 20, 30]); // r, g, b
+
+        let (rest, (id, action)) = read_action(&input).expect("known opcode should parse");
+        assert!(rest.is_empty());
+        assert_eq!(id, 1);
+        match action {
+            Action::PenColor(rgb) => assert_eq!((rgb.r, rgb.g, rgb.b), (10, 20, 30)),
+            other => panic!("expected PenColor, got {:?}", other),
+        }
+    }
+
+    fn is_too_large<'a>(err: &nom::Err<VerboseError<&'a[u8]>>) -> bool {
+        match err {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e.errors.iter()
+                .any(|(_, kind)| matches!(kind, VerboseErrorKind::Nom(ErrorKind::TooLarge))),
+            nom::Err::Incomplete(_) => false,
+        }
+    }
+
+    #[test]
+    fn length_count_rejects_a_declared_count_over_max_elements() {
+        let limits = ParseLimits { max_elements: 2, max_image_bytes: ParseLimits::default().max_image_bytes };
+        let mut input = Vec::new();
+        input.extend_from_slice(&3u32.to_le_bytes()); // declares 3 elements, over the limit of 2
+        input.extend_from_slice(&[0u8; 3 * 4]); // enough bytes that this isn't what trips the error
+
+        let parser = length_count(limits, 4, le_u32, map(le_u32, |t: u32| t.to_usize()));
+        let err = parser(&input).expect_err("a count over max_elements should be rejected");
+        assert!(is_too_large(&err), "expected a TooLarge error, got {:?}", err);
+    }
+
+    #[test]
+    fn length_count_rejects_a_declared_count_too_big_for_the_remaining_input() {
+        let limits = ParseLimits::default();
+        let mut input = Vec::new();
+        // Plausible under max_elements, but the element size (4 bytes) means
+        // this many elements could never fit in the one 4-byte element that
+        // actually follows.
+        input.extend_from_slice(&1000u32.to_le_bytes());
+        input.extend_from_slice(&[0u8; 4]);
+
+        let parser = length_count(limits, 4, le_u32, map(le_u32, |t: u32| t.to_usize()));
+        let err = parser(&input).expect_err("a count that can't fit in the remaining input should be rejected");
+        assert!(is_too_large(&err), "expected a TooLarge error, got {:?}", err);
+    }
+
+    #[test]
+    fn length_data_bounded_rejects_a_declared_length_over_max_bytes() {
+        let mut input = Vec::new();
+        input.extend_from_slice(&100u32.to_le_bytes()); // declares 100 bytes, over the limit of 16
+        input.extend_from_slice(&[0u8; 100]);
+
+        let parser = length_data_bounded::<VerboseError<&[u8]>>(16);
+        let err = parser(&input).expect_err("a length over max_bytes should be rejected");
+        assert!(is_too_large(&err), "expected a TooLarge error, got {:?}", err);
+    }
+
+    #[test]
+    fn read_image_with_limits_rejects_oversized_declared_image_data() {
+        let limits = ParseLimits { max_elements: ParseLimits::default().max_elements, max_image_bytes: 16 };
+        let mut input = Vec::new();
+        input.extend_from_slice(&1u32.to_le_bytes()); // kind
+        input.extend_from_slice(&100u32.to_le_bytes()); // declares 100 bytes of image data
+        input.extend_from_slice(&[0u8; 100]);
+
+        match read_image_with_limits(&input, &limits) {
+            Ok(_) => panic!("image data over max_image_bytes should be rejected"),
+            Err(err) => assert!(is_too_large(&err), "expected a TooLarge error, got {:?}", err),
+        }
+    }
 }
@@ -0,0 +1,692 @@
+//! Action-replay rasterizer: walks `ArtFile::actions` in order, the way
+//! the original editor would have replayed its undo/redo log, and
+//! composites the result into a single flattened RGBA8 raster.
+//!
+//! The format doesn't declare a canvas size anywhere in `ArtFile`, so the
+//! caller picks one via `RenderOptions` -- typically whatever viewport
+//! size the original editor used.
+
+use art::{Action, ArtError, ArtFile, Image, ImageFormat, PenInfo, StrokePoint, RGB};
+
+/// A flattened RGBA8 raster produced by `render`.
+pub struct RenderedImage {
+    pub width: u32,
+    pub height: u32,
+    /// Straight-alpha RGBA8, row-major, no row padding.
+    pub pixels: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+}
+
+const IDENTITY: [f32; 16] = [
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 1.0, 0.0,
+    0.0, 0.0, 0.0, 1.0,
+];
+
+/// Applies a column-major 4x4 transform -- the layout `view_matrix`,
+/// `PenTransform` and `LayerTransform`/`LayerInfo::matrix` all use -- to
+/// a 2D point, ignoring the z/w rows since every matrix in this format
+/// is used to place 2D strokes and layers.
+fn transform_point(m: &[f32; 16], zoom: f32, x: f32, y: f32) -> (f32, f32) {
+    let tx = m[0] * x + m[4] * y + m[12];
+    let ty = m[1] * x + m[5] * y + m[13];
+    (tx * zoom, ty * zoom)
+}
+
+#[derive(Clone)]
+struct LayerBuffer {
+    opacity: f32,
+    matrix: [f32; 16],
+    zoom: f32,
+    width: u32,
+    height: u32,
+    /// Straight-alpha RGBA, one f32 per channel.
+    pixels: Vec<[f32; 4]>,
+}
+
+impl LayerBuffer {
+    fn new(width: u32, height: u32) -> LayerBuffer {
+        LayerBuffer {
+            opacity: 1.0,
+            matrix: IDENTITY,
+            zoom: 1.0,
+            width,
+            height,
+            pixels: vec![[0.0; 4]; (width as usize) * (height as usize)],
+        }
+    }
+
+    /// Alpha-composites `color` (straight alpha, `color[3]` the coverage
+    /// of this one sample) onto the pixel at `(x, y)`. `erase` instead
+    /// treats `color[3]` as how much of the existing pixel to knock out
+    /// (a destination-out blend), for `PenIsEraser` strokes.
+    fn blend_pixel(&mut self, x: i32, y: i32, color: [f32; 4], erase: bool) {
+        if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+            return;
+        }
+        let idx = (y as u32 * self.width + x as u32) as usize;
+        let dst = &mut self.pixels[idx];
+        if erase {
+            dst[3] *= 1.0 - color[3];
+        } else {
+            let src_a = color[3];
+            let out_a = src_a + dst[3] * (1.0 - src_a);
+            if out_a > 0.0 {
+                for c in 0..3 {
+                    dst[c] = (color[c] * src_a + dst[c] * dst[3] * (1.0 - src_a)) / out_a;
+                }
+            }
+            dst[3] = out_a;
+        }
+    }
+}
+
+struct PenState {
+    info: PenInfo,
+    transform: [f32; 16],
+    zoom: f32,
+}
+
+/// Replays `art.actions` onto `opts.width` x `opts.height` layer buffers
+/// and flattens them, in `art.layer_order`, into a single raster.
+pub fn render(art: &ArtFile, opts: RenderOptions) -> Result<RenderedImage, ArtError> {
+    let mut layers: Vec<LayerBuffer> = art.layers.iter().map(|info| {
+        let mut buf = LayerBuffer::new(opts.width, opts.height);
+        buf.opacity = info.opacity;
+        buf.matrix = info.matrix;
+        buf.zoom = info.zoom;
+        buf
+    }).collect();
+    if layers.is_empty() {
+        layers.push(LayerBuffer::new(opts.width, opts.height));
+    }
+
+    // Nothing in `Action` switches which layer is being drawn to -- every
+    // stroke/image/cut in the log applies to the file's declared
+    // `active_layer`.
+    let current_layer = art.active_layer.min(layers.len() - 1);
+
+    let mut pen = PenState {
+        info: PenInfo {
+            kind: art.pen_info.kind,
+            color: RGB { r: art.pen_info.color.r, g: art.pen_info.color.g, b: art.pen_info.color.b },
+            noise: art.pen_info.noise,
+            size: art.pen_info.size,
+            size_min: art.pen_info.size_min,
+            opacity: art.pen_info.opacity,
+            opacity_min: art.pen_info.opacity_min,
+            is_eraser: art.pen_info.is_eraser,
+        },
+        transform: IDENTITY,
+        zoom: 1.0,
+    };
+
+    for (_, action) in &art.actions {
+        match action {
+            Action::PenTransform { matrix, zoom } => {
+                pen.transform = *matrix;
+                pen.zoom = *zoom;
+            }
+            Action::PenProperties(update) => {
+                pen.info.kind = update.kind;
+                pen.info.noise = update.noise;
+                pen.info.size = update.size;
+                pen.info.size_min = update.size_min;
+                pen.info.opacity = update.opacity;
+                pen.info.opacity_min = update.opacity_min;
+            }
+            Action::PenColor(rgb) => {
+                pen.info.color = RGB { r: rgb.r, g: rgb.g, b: rgb.b };
+            }
+            Action::PenIsEraser(is_eraser) => {
+                pen.info.is_eraser = *is_eraser;
+            }
+            Action::LayerTransform { matrix, zoom } => {
+                if let Some(layer) = layers.get_mut(current_layer) {
+                    layer.matrix = *matrix;
+                    layer.zoom = *zoom;
+                }
+            }
+            Action::LayerMerge { other, opacity_src, opacity_dst, .. } => {
+                merge_layers(&mut layers, *other, current_layer, *opacity_src, *opacity_dst);
+            }
+            Action::Stroke { points } => {
+                draw_stroke(&mut layers, current_layer, &pen, points);
+            }
+            Action::DrawImage { dst_center, dst_size, src_size, image_id, .. } => {
+                if let Some(image) = art.images.get(*image_id) {
+                    draw_image(&mut layers, current_layer, image, *dst_center, *dst_size, *src_size)?;
+                }
+            }
+            Action::CutRect { rect } => {
+                cut_rect(&mut layers, current_layer, *rect);
+            }
+            Action::PasteLayer(props) => {
+                paste_layer(&mut layers, current_layer, props.from_layer);
+            }
+            Action::_Unknown05(_) | Action::_Unknown08(_) | Action::UnknownOpcode { .. } => {}
+        }
+    }
+
+    let mut out = vec![0u8; (opts.width as usize) * (opts.height as usize) * 4];
+    for &layer_index in &art.layer_order {
+        if let Some(layer) = layers.get(layer_index) {
+            composite_layer_onto(&mut out, layer);
+        }
+    }
+
+    Ok(RenderedImage { width: opts.width, height: opts.height, pixels: out })
+}
+
+fn draw_stroke(layers: &mut [LayerBuffer], layer_index: usize, pen: &PenState, points: &[StrokePoint]) {
+    let layer = match layers.get_mut(layer_index) {
+        Some(layer) => layer,
+        None => return,
+    };
+    let color = [
+        pen.info.color.r as f32 / 255.0,
+        pen.info.color.g as f32 / 255.0,
+        pen.info.color.b as f32 / 255.0,
+    ];
+
+    let mut prev: Option<StrokeVertex> = None;
+    for point in points {
+        let (px, py) = transform_point(&pen.transform, pen.zoom, point.x, point.y);
+        let (sx, sy) = transform_point(&layer.matrix, layer.zoom, px, py);
+        // Interpolate the round-cap radius between size_min/2 and
+        // size/2 by pressure, the same way `opacity`/`opacity_min` scale
+        // coverage.
+        let radius = (pen.info.size_min + (pen.info.size - pen.info.size_min) * point.p).max(1.0) / 2.0;
+        let alpha = (pen.info.opacity_min + (pen.info.opacity - pen.info.opacity_min) * point.p).clamp(0.0, 1.0);
+
+        let vertex = StrokeVertex { x: sx, y: sy, r: radius };
+        match prev {
+            Some(prev_vertex) => stamp_segment(layer, prev_vertex, vertex, color, alpha, pen.info.is_eraser),
+            None => stamp_circle(layer, vertex.x, vertex.y, vertex.r, color, alpha, pen.info.is_eraser),
+        }
+        prev = Some(vertex);
+    }
+}
+
+/// One endpoint of a stroke segment, bundled so `stamp_segment` doesn't
+/// need two separate `(f32, f32, f32)` triples as arguments.
+#[derive(Clone, Copy)]
+struct StrokeVertex {
+    x: f32,
+    y: f32,
+    r: f32,
+}
+
+fn stamp_circle(layer: &mut LayerBuffer, cx: f32, cy: f32, r: f32, color: [f32; 3], alpha: f32, erase: bool) {
+    // A huge pen size (attacker-controlled via `PenProperties`) would
+    // otherwise turn this into an unbounded nested loop -- from a center on
+    // the canvas nothing farther than the canvas's own diagonal can ever
+    // land on a pixel, so that's as far as the radius needs to reach. A
+    // stamp centered off the canvas gets the same cap, so it can cover less
+    // than its full radius would. `f32::min` also folds a NaN radius down
+    // to this same safe cap.
+    let max_radius = ((layer.width as f32).powi(2) + (layer.height as f32).powi(2)).sqrt();
+    let r = r.min(max_radius);
+    let r_ceil = r.ceil() as i32;
+    let cx_i = cx.round() as i32;
+    let cy_i = cy.round() as i32;
+    for dy in -r_ceil..=r_ceil {
+        for dx in -r_ceil..=r_ceil {
+            if (dx * dx + dy * dy) as f32 <= r * r {
+                layer.blend_pixel(cx_i + dx, cy_i + dy, [color[0], color[1], color[2], alpha], erase);
+            }
+        }
+    }
+}
+
+/// Fills the round-cap capsule between two pressure-scaled circles by
+/// stamping circles along the segment, at most one pixel apart for
+/// segments within the step cap below.
+fn stamp_segment(
+    layer: &mut LayerBuffer,
+    from: StrokeVertex,
+    to: StrokeVertex,
+    color: [f32; 3], alpha: f32, erase: bool,
+) {
+    let dist = ((to.x - from.x).powi(2) + (to.y - from.y).powi(2)).sqrt();
+    // Caps the step count at the canvas's longer side plus one, so a huge
+    // jump between two stroke points (attacker-controlled coordinates)
+    // can't turn this into an unbounded loop. A segment longer than that
+    // is stamped at wider than one-pixel spacing and can leave gaps.
+    let max_steps = (layer.width.max(layer.height) as usize).saturating_add(1);
+    let steps = (dist.ceil() as usize).max(1).min(max_steps);
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let x = from.x + (to.x - from.x) * t;
+        let y = from.y + (to.y - from.y) * t;
+        let r = from.r + (to.r - from.r) * t;
+        stamp_circle(layer, x, y, r, color, alpha, erase);
+    }
+}
+
+fn draw_image(
+    layers: &mut [LayerBuffer],
+    layer_index: usize,
+    image: &Image,
+    dst_center: [f32; 2],
+    dst_size: [f32; 2],
+    src_size: [u32; 2],
+) -> Result<(), ArtError> {
+    let decoded = match image.format() {
+        ImageFormat::RawRgba => image.decode_with_size(src_size[0], src_size[1])?,
+        _ => image.decode()?,
+    };
+    if decoded.width == 0 || decoded.height == 0 {
+        return Ok(());
+    }
+    let layer = match layers.get_mut(layer_index) {
+        Some(layer) => layer,
+        None => return Ok(()),
+    };
+
+    let left = dst_center[0] - dst_size[0] / 2.0;
+    let top = dst_center[1] - dst_size[1] / 2.0;
+    let out_w = dst_size[0].round().max(1.0) as i32;
+    let out_h = dst_size[1].round().max(1.0) as i32;
+    let left_i = left.round() as i32;
+    let top_i = top.round() as i32;
+
+    // `dst_size` (and so `out_w`/`out_h`) is attacker-controlled and can be
+    // enormous; only the part of the destination rect that actually lands
+    // on the canvas needs visiting, which bounds this to the canvas's own
+    // pixel count no matter how large the requested placement is. `out_w`/
+    // `out_h` themselves stay unclamped so the u/v scale below still maps
+    // the full logical image, just clipped to what's visible.
+    let ox_start = (-left_i).max(0);
+    let ox_end = out_w.min(layer.width as i32 - left_i);
+    let oy_start = (-top_i).max(0);
+    let oy_end = out_h.min(layer.height as i32 - top_i);
+
+    for oy in oy_start..oy_end {
+        for ox in ox_start..ox_end {
+            let u = ox as f32 / out_w as f32;
+            let v = oy as f32 / out_h as f32;
+            let sx = (u * decoded.width as f32) as u32;
+            let sy = (v * decoded.height as f32) as u32;
+            if sx >= decoded.width || sy >= decoded.height {
+                continue;
+            }
+            let si = ((sy * decoded.width + sx) * 4) as usize;
+            let color = [
+                decoded.pixels[si] as f32 / 255.0,
+                decoded.pixels[si + 1] as f32 / 255.0,
+                decoded.pixels[si + 2] as f32 / 255.0,
+                decoded.pixels[si + 3] as f32 / 255.0,
+            ];
+            layer.blend_pixel(left_i + ox, top_i + oy, color, false);
+        }
+    }
+    Ok(())
+}
+
+fn cut_rect(layers: &mut [LayerBuffer], layer_index: usize, rect: [f32; 4]) {
+    let layer = match layers.get_mut(layer_index) {
+        Some(layer) => layer,
+        None => return,
+    };
+    // `rect` is attacker-controlled; clamp to the canvas before looping so
+    // an enormous rect can't turn this into an unbounded scan -- anything
+    // outside `[0, width) x [0, height)` would be a no-op in `blend_pixel`
+    // anyway.
+    let max_x = layer.width as f32;
+    let max_y = layer.height as f32;
+    let x0 = rect[0].min(rect[2]).floor().clamp(0.0, max_x) as i32;
+    let x1 = rect[0].max(rect[2]).ceil().clamp(0.0, max_x) as i32;
+    let y0 = rect[1].min(rect[3]).floor().clamp(0.0, max_y) as i32;
+    let y1 = rect[1].max(rect[3]).ceil().clamp(0.0, max_y) as i32;
+    for y in y0..y1 {
+        for x in x0..x1 {
+            layer.blend_pixel(x, y, [0.0, 0.0, 0.0, 1.0], true);
+        }
+    }
+}
+
+/// Composites `from_layer`'s buffer onto `layer_index`'s, straight-alpha
+/// over, at identity placement. `PasteProps` also carries a source rect
+/// and two placement transforms, but without a real sample file to
+/// confirm which transform anchors which corner this stays a same-size
+/// copy rather than guessing at a warp.
+fn paste_layer(layers: &mut [LayerBuffer], layer_index: usize, from_layer: usize) {
+    if from_layer == layer_index || from_layer >= layers.len() || layer_index >= layers.len() {
+        return;
+    }
+    let src_pixels = layers[from_layer].pixels.clone();
+    let dst = &mut layers[layer_index];
+    for (i, src) in src_pixels.iter().enumerate() {
+        let src_a = src[3];
+        if src_a <= 0.0 {
+            continue;
+        }
+        let d = &mut dst.pixels[i];
+        let out_a = src_a + d[3] * (1.0 - src_a);
+        if out_a > 0.0 {
+            for c in 0..3 {
+                d[c] = (src[c] * src_a + d[c] * d[3] * (1.0 - src_a)) / out_a;
+            }
+        }
+        d[3] = out_a;
+    }
+}
+
+fn merge_layers(layers: &mut [LayerBuffer], other: usize, dst_index: usize, opacity_src: f32, opacity_dst: f32) {
+    if other == dst_index || other >= layers.len() || dst_index >= layers.len() {
+        return;
+    }
+    let src_pixels = layers[other].pixels.clone();
+    let dst = &mut layers[dst_index];
+    for (i, src) in src_pixels.iter().enumerate() {
+        let src_a = src[3] * opacity_src;
+        let d = &mut dst.pixels[i];
+        d[3] *= opacity_dst;
+        let out_a = src_a + d[3] * (1.0 - src_a);
+        if out_a > 0.0 {
+            for c in 0..3 {
+                d[c] = (src[c] * src_a + d[c] * d[3] * (1.0 - src_a)) / out_a;
+            }
+        }
+        d[3] = out_a;
+    }
+}
+
+/// Encodes a rendered raster as PNG bytes, reusing the same `image` crate
+/// `Image::decode` already depends on.
+pub fn encode_png(img: &RenderedImage) -> Result<Vec<u8>, ArtError> {
+    let mut out = Vec::new();
+    image::codecs::png::PngEncoder::new(&mut out)
+        .encode(&img.pixels, img.width, img.height, image::ColorType::Rgba8)
+        .map_err(|e| ArtError::ParsingError(format!("failed to encode png: {}", e)))?;
+    Ok(out)
+}
+
+fn composite_layer_onto(out: &mut [u8], layer: &LayerBuffer) {
+    for (i, src) in layer.pixels.iter().enumerate() {
+        let src_a = src[3] * layer.opacity;
+        if src_a <= 0.0 {
+            continue;
+        }
+        let o = i * 4;
+        let dst_a = out[o + 3] as f32 / 255.0;
+        let out_a = src_a + dst_a * (1.0 - src_a);
+        if out_a > 0.0 {
+            for c in 0..3 {
+                let dst_c = out[o + c] as f32 / 255.0;
+                let blended = (src[c] * src_a + dst_c * dst_a * (1.0 - src_a)) / out_a;
+                out[o + c] = (blended * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        out[o + 3] = (out_a * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use art::{LayerInfo, PasteProps};
+
+    fn blank_art_file(actions: Vec<(usize, Action)>) -> ArtFile {
+        ArtFile {
+            version: 0,
+            active_layer: 0,
+            background_color: RGB { r: 0, g: 0, b: 0 },
+            background_alpha: 0.0,
+            pen_info: PenInfo {
+                kind: 0,
+                color: RGB { r: 255, g: 0, b: 0 },
+                noise: 0.0,
+                size: 2.0,
+                size_min: 2.0,
+                opacity: 1.0,
+                opacity_min: 1.0,
+                is_eraser: false,
+            },
+            view_matrix: IDENTITY,
+            view_zoom: 1.0,
+            pins: Vec::new(),
+            layer_order: vec![0],
+            layers: vec![LayerInfo {
+                visible: true,
+                opacity: 1.0,
+                name: String::from("layer 0"),
+                action_count: actions.len() as u32,
+                matrix: IDENTITY,
+                zoom: 1.0,
+            }],
+            images: Vec::new(),
+            actions,
+        }
+    }
+
+    fn pixel_at(img: &RenderedImage, x: u32, y: u32) -> [u8; 4] {
+        let o = ((y * img.width + x) * 4) as usize;
+        [img.pixels[o], img.pixels[o + 1], img.pixels[o + 2], img.pixels[o + 3]]
+    }
+
+    #[test]
+    fn render_with_no_actions_produces_a_fully_transparent_canvas() {
+        let art = blank_art_file(Vec::new());
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+        assert_eq!(img.pixels, vec![0u8; 4 * 4 * 4]);
+    }
+
+    #[test]
+    fn render_draws_a_stroke_point_as_an_opaque_pixel() {
+        let points = vec![StrokePoint { x: 2.0, y: 2.0, p: 1.0 }];
+        let art = blank_art_file(vec![(0, Action::Stroke { points })]);
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+
+        assert_eq!(pixel_at(&img, 2, 2), [255, 0, 0, 255]);
+        assert_eq!(pixel_at(&img, 0, 0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encode_png_round_trips_through_the_image_crate() {
+        let points = vec![StrokePoint { x: 2.0, y: 2.0, p: 1.0 }];
+        let art = blank_art_file(vec![(0, Action::Stroke { points })]);
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+
+        let png_bytes = encode_png(&img).expect("encoding should succeed");
+        let decoded = image::load_from_memory(&png_bytes).expect("the encoded PNG should decode").to_rgba8();
+        assert_eq!(decoded.width(), 4);
+        assert_eq!(decoded.height(), 4);
+        assert_eq!(decoded.into_raw(), img.pixels);
+    }
+
+    #[test]
+    fn render_connects_a_multi_point_stroke_with_no_gaps() {
+        let points = vec![
+            StrokePoint { x: 0.0, y: 2.0, p: 1.0 },
+            StrokePoint { x: 3.0, y: 2.0, p: 1.0 },
+        ];
+        let art = blank_art_file(vec![(0, Action::Stroke { points })]);
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+
+        for x in 0..4 {
+            assert_eq!(pixel_at(&img, x, 2), [255, 0, 0, 255], "gap at x={}", x);
+        }
+    }
+
+    #[test]
+    fn render_applies_pen_transform_before_stamping_a_stroke() {
+        let mut matrix = IDENTITY;
+        matrix[12] = 2.0; // translate x by 2
+        matrix[13] = 1.0; // translate y by 1
+        let points = vec![StrokePoint { x: 0.0, y: 0.0, p: 1.0 }];
+        let art = blank_art_file(vec![
+            (0, Action::PenTransform { matrix, zoom: 1.0 }),
+            (1, Action::Stroke { points }),
+        ]);
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+
+        assert_eq!(pixel_at(&img, 2, 1), [255, 0, 0, 255]);
+        assert_eq!(pixel_at(&img, 0, 0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn render_applies_layer_transform_before_stamping_a_stroke() {
+        let mut matrix = IDENTITY;
+        matrix[12] = 1.0;
+        matrix[13] = 2.0;
+        let points = vec![StrokePoint { x: 0.0, y: 0.0, p: 1.0 }];
+        let art = blank_art_file(vec![
+            (0, Action::LayerTransform { matrix, zoom: 1.0 }),
+            (1, Action::Stroke { points }),
+        ]);
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+
+        assert_eq!(pixel_at(&img, 1, 2), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn render_erases_instead_of_painting_once_pen_is_eraser() {
+        let first = vec![StrokePoint { x: 2.0, y: 2.0, p: 1.0 }];
+        let second = vec![StrokePoint { x: 2.0, y: 2.0, p: 1.0 }];
+        let art = blank_art_file(vec![
+            (0, Action::Stroke { points: first }),
+            (1, Action::PenIsEraser(true)),
+            (2, Action::Stroke { points: second }),
+        ]);
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+
+        assert_eq!(pixel_at(&img, 2, 2), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn render_cut_rect_knocks_out_painted_pixels() {
+        let points = vec![StrokePoint { x: 1.0, y: 1.0, p: 1.0 }, StrokePoint { x: 2.0, y: 1.0, p: 1.0 }];
+        let art = blank_art_file(vec![
+            (0, Action::Stroke { points }),
+            (1, Action::CutRect { rect: [0.0, 0.0, 2.0, 2.0] }),
+        ]);
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+
+        assert_eq!(pixel_at(&img, 1, 1), [0, 0, 0, 0]);
+        assert_eq!(pixel_at(&img, 2, 1), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn render_cut_rect_with_a_huge_rect_clamps_to_the_canvas_instead_of_hanging() {
+        let art = blank_art_file(vec![
+            (0, Action::CutRect { rect: [-1e8, -1e8, 1e8, 1e8] }),
+        ]);
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+        assert_eq!(img.pixels, vec![0u8; 4 * 4 * 4]);
+    }
+
+    #[test]
+    fn render_stroke_with_a_huge_pen_size_clamps_instead_of_hanging() {
+        let points = vec![StrokePoint { x: 2.0, y: 2.0, p: 1.0 }];
+        let mut art = blank_art_file(vec![(0, Action::Stroke { points })]);
+        art.pen_info.size = 1e8;
+        art.pen_info.size_min = 1e8;
+
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+
+        // The whole canvas should be painted -- a huge radius clamped to
+        // the canvas size, not an unbounded loop that never returns.
+        assert_eq!(pixel_at(&img, 0, 0), [255, 0, 0, 255]);
+        assert_eq!(pixel_at(&img, 3, 3), [255, 0, 0, 255]);
+    }
+
+    fn two_layer_art_file(actions: Vec<(usize, Action)>) -> ArtFile {
+        let mut art = blank_art_file(actions);
+        art.layer_order = vec![0, 1];
+        art.layers.push(LayerInfo {
+            visible: true,
+            opacity: 1.0,
+            name: String::from("layer 1"),
+            action_count: 0,
+            matrix: IDENTITY,
+            zoom: 1.0,
+        });
+        art
+    }
+
+    #[test]
+    fn render_layer_merge_composites_the_other_layer_in() {
+        let points = vec![StrokePoint { x: 2.0, y: 2.0, p: 1.0 }];
+        let mut art = two_layer_art_file(vec![(0, Action::Stroke { points })]);
+        art.active_layer = 1;
+        art.actions.push((1, Action::LayerMerge {
+            other: 0,
+            opacity_src: 1.0,
+            opacity_dst: 1.0,
+            matrix: IDENTITY,
+            zoom: 1.0,
+        }));
+
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+        assert_eq!(pixel_at(&img, 2, 2), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn render_paste_layer_copies_the_source_layer_onto_the_active_one() {
+        let points = vec![StrokePoint { x: 2.0, y: 2.0, p: 1.0 }];
+        let mut art = two_layer_art_file(vec![(0, Action::Stroke { points })]);
+        art.active_layer = 1;
+        art.actions.push((1, Action::PasteLayer(PasteProps {
+            from_layer: 0,
+            rect: [0.0, 0.0, 4.0, 4.0],
+            matrix1: IDENTITY,
+            zoom1: 1.0,
+            matrix2: IDENTITY,
+            zoom2: 1.0,
+        })));
+
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+        assert_eq!(pixel_at(&img, 2, 2), [255, 0, 0, 255]);
+    }
+
+    fn encode_test_png(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+        let img = image::RgbaImage::from_raw(width, height, pixels.to_vec())
+            .expect("test pixel buffer should match width/height");
+        let mut out = Vec::new();
+        image::DynamicImage::ImageRgba8(img)
+            .write_to(&mut out, image::ImageOutputFormat::Png)
+            .expect("encoding the test PNG should succeed");
+        out
+    }
+
+    #[test]
+    fn render_draw_image_blits_a_decoded_image_onto_the_canvas() {
+        let pixels = [10u8, 20, 30, 255, 40, 50, 60, 255, 70, 80, 90, 255, 100, 110, 120, 255];
+        let mut art = blank_art_file(vec![(0, Action::DrawImage {
+            dst_center: [2.0, 2.0],
+            dst_size: [2.0, 2.0],
+            _unknown: 0,
+            src_size: [0, 0],
+            image_id: 0,
+        })]);
+        art.images.push(Image { kind: 0, raw: encode_test_png(2, 2, &pixels) });
+
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+        assert_eq!(pixel_at(&img, 1, 1), [10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn render_draw_image_with_a_huge_dst_size_clamps_to_the_canvas_instead_of_hanging() {
+        // A bare 4-byte buffer sniffs as `ImageFormat::RawRgba`, whose
+        // dimensions come from `DrawImage::src_size` rather than the file.
+        let mut art = blank_art_file(vec![(0, Action::DrawImage {
+            dst_center: [2.0, 2.0],
+            dst_size: [1e8, 1e8],
+            _unknown: 0,
+            src_size: [1, 1],
+            image_id: 0,
+        })]);
+        art.images.push(Image { kind: 0, raw: vec![200u8, 0, 0, 255] });
+
+        let img = render(&art, RenderOptions { width: 4, height: 4 }).expect("render should succeed");
+        assert_eq!(img.pixels.len(), 4 * 4 * 4);
+    }
+}